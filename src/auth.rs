@@ -0,0 +1,72 @@
+//! Password hashing and verification for locked rooms, mirroring lavina's
+//! argon2-based SASL credential checks.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+/// Hash a freshly-chosen room password for storage.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 hashing cannot fail")
+        .to_string()
+}
+
+/// Verify a candidate password against a hash produced by [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok()
+}
+
+/// Compares two byte strings in constant time, so comparing a secret (a
+/// room token, an admin bearer token) against a caller-supplied value
+/// doesn't leak how many leading bytes matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_password_accepts_the_matching_password() {
+        let hash = hash_password("correct horse battery staple");
+        assert!(verify_password("correct horse battery staple", &hash));
+    }
+
+    #[test]
+    fn verify_password_rejects_a_wrong_password() {
+        let hash = hash_password("correct horse battery staple");
+        assert!(!verify_password("wrong password", &hash));
+    }
+
+    #[test]
+    fn verify_password_rejects_a_malformed_hash() {
+        assert!(!verify_password("anything", "not a real hash"));
+    }
+
+    #[test]
+    fn constant_time_eq_matches_identical_bytes() {
+        assert!(constant_time_eq(b"the-same-secret", b"the-same-secret"));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_bytes_of_the_same_length() {
+        assert!(!constant_time_eq(b"the-same-secret", b"the-diff-secret"));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_lengths() {
+        assert!(!constant_time_eq(b"short", b"a much longer secret"));
+    }
+}
@@ -1,43 +1,97 @@
-use std::{
-    net::SocketAddr,
-    path::Path,
-    sync::atomic::{AtomicU32, Ordering},
-};
+mod auth;
+mod mgmt;
+mod metrics;
+mod room;
+mod shutdown;
+mod storage;
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
-    extract::{self, ws::WebSocket, WebSocketUpgrade},
-    response::Response,
+    extract::{
+        self,
+        ws::{CloseFrame, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
+use clap::Parser;
+use figment::providers::{Env, Format, Toml};
+use figment::Figment;
 use serde::{Deserialize, Serialize};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
 
-static PC: AtomicU32 = AtomicU32::new(3);
+use room::{Role, RoomHandle, RoomRegistry, PC};
+use shutdown::shutdown_signal;
+use storage::Storage;
+
+/// Runs a CrackBox server.
+#[derive(Parser)]
+#[command(name = "crackbox")]
+struct Args {
+    /// Path to the TOML config file. Settings can be overridden per
+    /// deployment with `CRACKBOX_`-prefixed environment variables, with `__`
+    /// marking a nested key, e.g. `CRACKBOX_SERVER__BIND`.
+    #[arg(long, default_value = "./config.toml")]
+    config: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    tls: Option<Tls>,
+    server: Server,
+    storage: StorageConfig,
+    mgmt: MgmtConfig,
+    /// Per-game settings, keyed by the `game_name` Jackbox's client asks
+    /// `/api/v2/app-configs/:game_name` for. Lets one CrackBox instance
+    /// serve several titles instead of only ever answering `antique-freak`.
+    #[serde(default)]
+    games: HashMap<String, GameConfig>,
+}
 
 #[derive(Deserialize)]
-struct Config<'a> {
-    #[serde(borrow)]
-    tls: Option<Tls<'a>>,
-    #[serde(borrow)]
-    server: Server<'a>,
+struct MgmtConfig {
+    /// Bearer token required on `/mgmt/*` routes.
+    token: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GameConfig {
+    #[serde(rename = "serverUrl")]
+    server_url: String,
+    #[serde(default)]
+    features: HashMap<String, bool>,
+    #[serde(default)]
+    assets: HashMap<String, String>,
 }
 
 #[derive(Deserialize)]
-struct Server<'a> {
-    #[serde(borrow)]
-    steam_apps_common: &'a Path,
+struct Server {
+    steam_apps_common: PathBuf,
     bind: SocketAddr,
 }
 
 #[derive(Deserialize)]
-struct Tls<'a> {
-    #[serde(borrow)]
-    key: &'a Path,
-    #[serde(borrow)]
-    cert: &'a Path,
+struct Tls {
+    key: PathBuf,
+    cert: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct StorageConfig {
+    path: PathBuf,
+    /// How long an abandoned room is kept before it's garbage-collected.
+    #[serde(default = "default_room_ttl_secs")]
+    room_ttl_secs: u64,
+}
+
+fn default_room_ttl_secs() -> u64 {
+    6 * 60 * 60
 }
 
 #[tokio::main]
@@ -54,16 +108,47 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let file = std::fs::File::open("./config.toml").unwrap();
-    let map = unsafe { memmap2::Mmap::map(&file).unwrap() };
-    let config: Config = toml::from_slice(map.as_ref()).unwrap();
+    let args = Args::parse();
+
+    let config: Config = Figment::new()
+        .merge(Toml::file(&args.config))
+        .merge(Env::prefixed("CRACKBOX_").split("__"))
+        .extract()
+        .unwrap_or_else(|err| panic!("failed to load config from {}: {}", args.config.display(), err));
+
+    let storage = Arc::new(
+        Storage::connect(&config.storage.path)
+            .await
+            .expect("failed to open room database"),
+    );
+    let room_ttl = Duration::from_secs(config.storage.room_ttl_secs);
+
+    let rooms = RoomRegistry::new(storage);
+    rooms.restore().await;
+    let gc = rooms.spawn_gc(room_ttl, Duration::from_secs(60));
+
+    let mgmt = mgmt::router(mgmt::MgmtState {
+        rooms: rooms.clone(),
+        token: config.mgmt.token.into(),
+    });
+    let games = GamesConfig(Arc::new(config.games));
 
     let app = Router::new()
         .route("/api/v2/rooms", post(make_room))
         .route("/api/v2/rooms/:room_id/play", get(room_upgrade))
-        .route("/api/v2/app-configs/:game_name", get(app_configs))
+        .with_state(rooms.clone())
+        .merge(
+            Router::new()
+                .route("/api/v2/app-configs/:game_name", get(app_configs))
+                .with_state(games),
+        )
+        .merge(mgmt)
+        .route("/metrics", get(|| async { metrics::render() }))
         .layer(TraceLayer::new_for_http());
 
+    let handle = axum_server::Handle::new();
+    tokio::spawn(shutdown(rooms, gc, handle.clone()));
+
     if let Some(tls) = config.tls {
         let rustls_config = RustlsConfig::from_pem_file(tls.cert, tls.key)
             .await
@@ -71,18 +156,32 @@ async fn main() {
 
         tracing::debug!("HTTPS server started on {}", config.server.bind);
         axum_server::bind_rustls(config.server.bind, rustls_config)
+            .handle(handle)
             .serve(app.into_make_service())
             .await
             .unwrap();
     } else {
         tracing::debug!("HTTP server started on {}", config.server.bind);
         axum_server::bind(config.server.bind)
+            .handle(handle)
             .serve(app.into_make_service())
             .await
             .unwrap();
     }
 }
 
+/// Waits for `SIGINT`/`SIGTERM`, tells every connected socket to close with
+/// a proper close frame, joins the room GC task, then stops the
+/// listener from accepting new connections.
+async fn shutdown(rooms: RoomRegistry, gc: shutdown::Terminator, handle: axum_server::Handle) {
+    shutdown_signal().await;
+    tracing::info!("shutting down");
+
+    rooms.begin_shutdown();
+    gc.terminate().await;
+    handle.graceful_shutdown(Some(Duration::from_secs(10)));
+}
+
 #[derive(Serialize)]
 struct RoomResponse {
     ok: bool,
@@ -92,30 +191,63 @@ struct RoomResponse {
 #[derive(Serialize)]
 struct RoomResponseBody {
     host: &'static str,
-    code: &'static str,
-    token: &'static str,
+    code: String,
+    token: String,
+}
+
+#[derive(Deserialize, Default)]
+struct MakeRoomRequest {
+    /// Locks the room: joining it will require this same password.
+    #[serde(default)]
+    password: Option<String>,
 }
 
-async fn make_room() -> Json<RoomResponse> {
+async fn make_room(
+    State(rooms): State<RoomRegistry>,
+    body: Option<Json<MakeRoomRequest>>,
+) -> Response {
+    let password = body.and_then(|Json(req)| req.password);
+    let room = match rooms.create_room(password.as_deref()).await {
+        Ok(room) => room,
+        Err(err) => {
+            tracing::error!("failed to create room: {}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "failed to create room").into_response();
+        }
+    };
     Json(RoomResponse {
         ok: true,
         body: RoomResponseBody {
             host: "lbssexercise.info",
-            code: "OKOK",
-            token: "000000000000000000000000",
+            code: room.code,
+            token: room.token,
         },
     })
+    .into_response()
 }
 
 #[derive(Deserialize)]
 struct RoomQuery {
     role: RoomRole,
     name: String,
+    #[allow(dead_code)]
     format: String,
     #[serde(rename = "user-id")]
     user_id: String,
+    /// The `secret` a previous `client/welcome` handed this client, sent
+    /// back so a refreshed page can rejoin its old seat.
+    secret: Option<String>,
+    /// Required to join a password-locked room.
+    password: Option<String>,
+    /// Required to join as `role=host`: the token `make_room` returned for
+    /// this room. Without it, anyone who guesses a room code could open a
+    /// socket as the host and bypass `can_write`'s player restrictions.
+    token: Option<String>,
 }
 
+/// WebSocket close code sent when a room's password doesn't match, mirroring
+/// lavina's `ERR_SASLFAIL` handling for failed credential checks.
+const WS_CLOSE_SASLFAIL: u16 = 4003;
+
 #[derive(Deserialize)]
 enum RoomRole {
     #[serde(rename = "player")]
@@ -127,12 +259,156 @@ enum RoomRole {
 async fn room_upgrade(
     extract::Path(room_id): extract::Path<String>,
     extract::Query(join_info): extract::Query<RoomQuery>,
+    State(rooms): State<RoomRegistry>,
     ws: WebSocketUpgrade,
 ) -> Response {
     tracing::debug!("Upgrading room socket for room {}", room_id);
-    match join_info.role {
-        RoomRole::Host => ws.on_upgrade(host_handler),
-        RoomRole::Player => ws.on_upgrade(player_handler),
+    let Some(room) = rooms.get(&room_id).await else {
+        return (StatusCode::NOT_FOUND, "no such room").into_response();
+    };
+
+    let role = match join_info.role {
+        RoomRole::Host => Role::Host,
+        RoomRole::Player => Role::Player,
+    };
+
+    if role == Role::Host {
+        let provided = join_info.token.as_deref().unwrap_or("");
+        if !auth::constant_time_eq(provided.as_bytes(), room.token.as_bytes()) {
+            tracing::debug!("rejecting host join to room {}: bad token", room_id);
+            return ws.on_upgrade(close_with_saslfail);
+        }
+    }
+
+    if let Some(hash) = room.password_hash.clone() {
+        let provided = join_info.password.unwrap_or_default();
+        if !auth::verify_password(&provided, &hash) {
+            tracing::debug!("rejecting join to room {}: bad password", room_id);
+            return ws.on_upgrade(close_with_saslfail);
+        }
+    }
+
+    let session = match resolve_session(
+        rooms.storage(),
+        &room,
+        &join_info.user_id,
+        join_info.secret.as_deref(),
+        role,
+    )
+    .await
+    {
+        SessionOutcome::Session(session) => session,
+        SessionOutcome::RoleMismatch => {
+            tracing::debug!(
+                "rejecting join to room {}: role does not match the reconnecting session",
+                room_id
+            );
+            return ws.on_upgrade(close_with_saslfail);
+        }
+    };
+
+    ws.on_upgrade(move |ws| socket_handler(ws, rooms, room, role, join_info.name, join_info.user_id, session))
+}
+
+/// Closes a freshly-upgraded socket with an auth-failure code, for joins
+/// that never make it past the room's token/password/role checks.
+async fn close_with_saslfail(mut ws: WebSocket) {
+    let _ = ws
+        .send(axum::extract::ws::Message::Close(Some(CloseFrame {
+            code: WS_CLOSE_SASLFAIL,
+            reason: "authentication failed".into(),
+        })))
+        .await;
+}
+
+/// A client's seat in a room: either a brand new id/secret pair, or a
+/// rejoin of a disconnected session that matched on `user-id` + `secret`.
+struct Session {
+    id: u32,
+    secret: String,
+    reconnect: bool,
+}
+
+enum SessionOutcome {
+    Session(Session),
+    /// A reconnect's `user-id`/`secret` matched a persisted client, but the
+    /// requested role doesn't match the role that client originally joined
+    /// with - e.g. a player replaying their own secret with `role=host`.
+    RoleMismatch,
+}
+
+async fn resolve_session(
+    storage: &Storage,
+    room: &RoomHandle,
+    user_id: &str,
+    secret: Option<&str>,
+    role: Role,
+) -> SessionOutcome {
+    if let Some(secret) = secret {
+        if let Ok(Some(persisted)) = storage.find_reconnect(&room.code, user_id, secret).await {
+            return reconnect_outcome(persisted, role);
+        }
+    }
+
+    SessionOutcome::Session(Session {
+        id: room.allocate_client_id(),
+        secret: generate_secret(),
+        reconnect: false,
+    })
+}
+
+/// Decides whether a reconnecting client may resume `persisted`'s seat
+/// under `role`. A free function so this security-sensitive check - a
+/// player replaying their own secret with `role=host` must not be granted
+/// host privileges - is testable without a live `Storage`/`RoomHandle`.
+fn reconnect_outcome(persisted: storage::PersistedClient, role: Role) -> SessionOutcome {
+    if persisted.role != role {
+        return SessionOutcome::RoleMismatch;
+    }
+    SessionOutcome::Session(Session {
+        id: persisted.id,
+        secret: persisted.secret,
+        reconnect: true,
+    })
+}
+
+fn generate_secret() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..24).map(|_| rng.gen_range(0..10).to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn persisted(role: Role) -> storage::PersistedClient {
+        storage::PersistedClient {
+            id: 7,
+            user_id: "u1".to_string(),
+            secret: "s1".to_string(),
+            name: "alice".to_string(),
+            role,
+        }
+    }
+
+    #[test]
+    fn reconnect_matching_role_resumes_the_session() {
+        let outcome = reconnect_outcome(persisted(Role::Player), Role::Player);
+        match outcome {
+            SessionOutcome::Session(session) => {
+                assert_eq!(session.id, 7);
+                assert_eq!(session.secret, "s1");
+                assert!(session.reconnect);
+            }
+            SessionOutcome::RoleMismatch => panic!("expected a resumed session"),
+        }
+    }
+
+    #[test]
+    fn reconnect_with_escalated_role_is_rejected() {
+        let outcome = reconnect_outcome(persisted(Role::Player), Role::Host);
+        assert!(matches!(outcome, SessionOutcome::RoleMismatch));
     }
 }
 
@@ -146,70 +422,178 @@ struct HostWelcome {
 #[derive(Serialize)]
 struct HostWelcomeResult {
     id: u32,
-    secret: &'static str,
+    secret: String,
     reconnect: bool,
     #[serde(rename = "deviceId")]
     device_id: &'static str,
-    entities: (),
-    here: (),
+    entities: std::collections::HashMap<String, room::Entity>,
+    here: Vec<room::ClientSummary>,
     profile: Option<HostWelcomeProfile>,
 }
 
 #[derive(Serialize)]
 struct HostWelcomeProfile;
 
-async fn host_handler(mut ws: WebSocket) {
-    tracing::debug!("Sending host welcome");
-    ws.send(axum::extract::ws::Message::Text(
-        serde_json::to_string(&HostWelcome {
-            pc: PC.fetch_add(1, Ordering::AcqRel),
-            opcode: "client/welcome",
-            result: HostWelcomeResult {
-                id: 1,
-                secret: "000000000000000000000000",
-                reconnect: false,
-                device_id: "0000000000.0000000000000000000000",
-                entities: (),
-                here: (),
-                profile: None,
-            },
-        })
-        .unwrap(),
-    ))
-    .await
-    .unwrap();
+#[derive(Deserialize)]
+#[serde(tag = "opcode", rename_all = "snake_case")]
+enum ClientOpcode {
+    #[serde(rename = "object/set")]
+    ObjectSet { params: SetParams },
+    #[serde(rename = "text/set")]
+    TextSet { params: SetParams },
+    #[serde(rename = "client/send")]
+    ClientSend { params: SendParams },
+}
+
+#[derive(Deserialize)]
+struct SetParams {
+    key: String,
+    val: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    to: u32,
+    #[serde(flatten)]
+    val: serde_json::Value,
+}
+
+async fn socket_handler(
+    mut ws: WebSocket,
+    rooms: RoomRegistry,
+    room: RoomHandle,
+    role: Role,
+    name: String,
+    user_id: String,
+    session: Session,
+) {
+    let id = session.id;
+    let storage = rooms.storage();
+    let connected_gauge = match role {
+        Role::Host => &metrics::CONNECTED_HOSTS,
+        Role::Player => &metrics::CONNECTED_PLAYERS,
+    };
+    connected_gauge.inc();
+
+    if let Err(err) = storage
+        .upsert_client(&room.code, id, &user_id, &session.secret, &name, role, true)
+        .await
+    {
+        tracing::warn!("failed to persist client {}/{}: {}", room.code, id, err);
+    }
+    if let Err(err) = storage.touch_room(&room.code, room::now()).await {
+        tracing::warn!("failed to touch room {}: {}", room.code, err);
+    }
+
+    let Some(room::JoinResult {
+        entities,
+        here,
+        mut broadcast_rx,
+    }) = room.join(id, name).await
+    else {
+        tracing::debug!("room {} closed before client {} finished joining", room.code, id);
+        let _ = storage.mark_disconnected(&room.code, id).await;
+        connected_gauge.dec();
+        return;
+    };
+
+    tracing::debug!("Sending welcome to client {} in room {}", id, room.code);
+    if ws
+        .send(axum::extract::ws::Message::Text(
+            serde_json::to_string(&HostWelcome {
+                pc: PC.fetch_add(1, std::sync::atomic::Ordering::AcqRel),
+                opcode: "client/welcome",
+                result: HostWelcomeResult {
+                    id,
+                    secret: session.secret,
+                    reconnect: session.reconnect,
+                    device_id: "0000000000.0000000000000000000000",
+                    entities,
+                    here,
+                    profile: None,
+                },
+            })
+            .expect("serializing the welcome cannot fail"),
+        ))
+        .await
+        .is_err()
+    {
+        room.leave(id).await;
+        let _ = storage.mark_disconnected(&room.code, id).await;
+        connected_gauge.dec();
+        return;
+    }
+
+    let mut shutdown_rx = rooms.subscribe_shutdown();
 
     loop {
-        ws.recv().await.unwrap().unwrap();
+        tokio::select! {
+            incoming = ws.recv() => {
+                match incoming {
+                    Some(Ok(axum::extract::ws::Message::Text(text))) => {
+                        handle_client_message(&room, id, role, &text).await;
+                    }
+                    Some(Ok(axum::extract::ws::Message::Close(_))) | None => break,
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        tracing::debug!("socket error for client {}: {}", id, err);
+                        break;
+                    }
+                }
+            }
+            event = broadcast_rx.recv() => {
+                match event {
+                    Ok(room::RoomBroadcast::Event { target, payload }) if target.is_none() || target == Some(id) => {
+                        if ws.send(axum::extract::ws::Message::Text(payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(room::RoomBroadcast::Event { .. }) => {}
+                    Ok(room::RoomBroadcast::Close) => {
+                        let _ = ws.send(axum::extract::ws::Message::Close(None)).await;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                let _ = ws.send(axum::extract::ws::Message::Close(None)).await;
+                break;
+            }
+        }
     }
+
+    room.leave(id).await;
+    let _ = storage.mark_disconnected(&room.code, id).await;
+    connected_gauge.dec();
 }
 
-async fn player_handler(mut ws: WebSocket) {
-    tracing::debug!("Sending player welcome");
-    ws.send(axum::extract::ws::Message::Text(
-        serde_json::to_string(&HostWelcome {
-            pc: PC.fetch_add(1, Ordering::AcqRel),
-            opcode: "client/welcome",
-            result: HostWelcomeResult {
-                id: 1,
-                secret: "000000000000000000000000",
-                reconnect: false,
-                device_id: "0000000000.0000000000000000000000",
-                entities: (),
-                here: (),
-                profile: None,
-            },
-        })
-        .unwrap(),
-    ))
-    .await
-    .unwrap();
+async fn handle_client_message(room: &RoomHandle, from: u32, role: Role, text: &str) {
+    metrics::WS_MESSAGES_TOTAL.inc();
 
-    loop {
-        ws.recv().await.unwrap().unwrap();
+    let opcode: ClientOpcode = match serde_json::from_str(text) {
+        Ok(opcode) => opcode,
+        Err(err) => {
+            tracing::debug!("ignoring unrecognized message from {}: {}", from, err);
+            return;
+        }
+    };
+
+    match opcode {
+        ClientOpcode::ObjectSet { params } | ClientOpcode::TextSet { params } => {
+            room.set_object(from, role, params.key, params.val).await;
+        }
+        ClientOpcode::ClientSend { params } => {
+            room.send_private(from, params.to, params.val).await;
+        }
     }
 }
 
+/// The per-game settings loaded from the `[games]` table in `config.toml`,
+/// shared (read-only) with the `app_configs` handler.
+#[derive(Clone)]
+struct GamesConfig(Arc<HashMap<String, GameConfig>>);
+
 #[derive(Serialize)]
 struct AppConfigs {
     ok: bool,
@@ -224,17 +608,27 @@ struct AppConfigsBody {
 #[derive(Serialize)]
 struct AppConfigsSettings {
     #[serde(rename = "serverUrl")]
-    server_url: &'static str,
+    server_url: String,
+    features: HashMap<String, bool>,
+    assets: HashMap<String, String>,
 }
 
-async fn app_configs(extract::Path(game_name): extract::Path<String>) -> Json<AppConfigs> {
-    assert_eq!(game_name, "antique-freak");
+async fn app_configs(
+    State(games): State<GamesConfig>,
+    extract::Path(game_name): extract::Path<String>,
+) -> Response {
+    let Some(game) = games.0.get(&game_name) else {
+        return (StatusCode::NOT_FOUND, "unknown game").into_response();
+    };
     Json(AppConfigs {
         ok: true,
         body: AppConfigsBody {
             settings: AppConfigsSettings {
-                server_url: "lbssexercise.info",
+                server_url: game.server_url.clone(),
+                features: game.features.clone(),
+                assets: game.assets.clone(),
             },
         },
     })
+    .into_response()
 }
@@ -0,0 +1,40 @@
+//! Prometheus metrics for operators watching a running deployment.
+
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+pub static ACTIVE_ROOMS: Lazy<IntGauge> = Lazy::new(|| register_gauge("crackbox_active_rooms", "Number of rooms currently open"));
+pub static CONNECTED_HOSTS: Lazy<IntGauge> = Lazy::new(|| register_gauge("crackbox_connected_hosts", "Number of connected host sockets"));
+pub static CONNECTED_PLAYERS: Lazy<IntGauge> = Lazy::new(|| register_gauge("crackbox_connected_players", "Number of connected player sockets"));
+pub static PC_CURRENT: Lazy<IntGauge> = Lazy::new(|| register_gauge("crackbox_pc_current", "Current value of the global Ecast packet counter"));
+pub static WS_MESSAGES_TOTAL: Lazy<IntCounter> = Lazy::new(|| register_counter("crackbox_ws_messages_total", "WebSocket messages processed"));
+
+fn register_gauge(name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("metric name/help are valid");
+    REGISTRY
+        .register(Box::new(gauge.clone()))
+        .expect("metric registered exactly once");
+    gauge
+}
+
+fn register_counter(name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("metric name/help are valid");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("metric registered exactly once");
+    counter
+}
+
+/// Render every registered metric in Prometheus text exposition format.
+pub fn render() -> String {
+    PC_CURRENT.set(crate::room::PC.load(std::sync::atomic::Ordering::Relaxed) as i64);
+
+    let metric_families = REGISTRY.gather();
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metric_families, &mut buffer)
+        .expect("encoding gathered metrics cannot fail");
+    String::from_utf8(buffer).expect("prometheus text output is always UTF-8")
+}
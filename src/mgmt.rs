@@ -0,0 +1,87 @@
+//! Authenticated admin routes for operating a running CrackBox deployment.
+
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::room::{ClientSummary, Entity, RoomRegistry, RoomSummary};
+
+#[derive(Clone)]
+pub struct MgmtState {
+    pub rooms: RoomRegistry,
+    pub token: std::sync::Arc<str>,
+}
+
+pub fn router(state: MgmtState) -> Router {
+    Router::new()
+        .route("/mgmt/rooms", get(list_rooms))
+        .route("/mgmt/rooms/:code", get(dump_room))
+        .route("/mgmt/rooms/:code/close", post(close_room))
+        .with_state(state)
+}
+
+async fn require_auth(state: &MgmtState, auth: Option<&str>) -> Result<(), Response> {
+    let expected = format!("Bearer {}", state.token);
+    let provided = auth.unwrap_or("");
+    if crate::auth::constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "missing or invalid admin token").into_response())
+    }
+}
+
+#[derive(Serialize)]
+struct RoomListResponse {
+    rooms: Vec<RoomSummary>,
+}
+
+async fn list_rooms(
+    State(state): State<MgmtState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Err(resp) = require_auth(&state, headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok())).await {
+        return resp;
+    }
+    Json(RoomListResponse {
+        rooms: state.rooms.list_summaries().await,
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct RoomDumpResponse {
+    entities: std::collections::HashMap<String, Entity>,
+    here: Vec<ClientSummary>,
+}
+
+async fn dump_room(
+    State(state): State<MgmtState>,
+    Path(code): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Err(resp) = require_auth(&state, headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok())).await {
+        return resp;
+    }
+    match state.rooms.dump(&code).await {
+        Some((entities, here)) => Json(RoomDumpResponse { entities, here }).into_response(),
+        None => (StatusCode::NOT_FOUND, "no such room").into_response(),
+    }
+}
+
+async fn close_room(
+    State(state): State<MgmtState>,
+    Path(code): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Err(resp) = require_auth(&state, headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok())).await {
+        return resp;
+    }
+    if state.rooms.close_room(&code).await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "no such room").into_response()
+    }
+}
@@ -0,0 +1,614 @@
+//! The Ecast-style entity store and room registry.
+//!
+//! Each room is an actor task: callers talk to it over a [`RoomCommand`]
+//! mpsc channel and it owns the entity map exclusively, so there's no
+//! locking across awaits. Room-wide events (object updates, private
+//! `client/send` relays) go out over a `broadcast` channel that every
+//! connected socket subscribes to on join.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+
+use crate::shutdown::Terminator;
+use crate::storage::Storage;
+
+/// Global packet counter, shared by every room, mirroring Ecast's `pc`.
+pub static PC: AtomicU32 = AtomicU32::new(3);
+
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const ROOM_CODE_LEN: usize = 4;
+const ROOM_EVENT_CHANNEL: usize = 64;
+const ROOM_COMMAND_CHANNEL: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Role {
+    #[serde(rename = "host")]
+    Host,
+    #[serde(rename = "player")]
+    Player,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Restrictions {
+    /// Client id that owns this key, or `None` for host-writable shared keys.
+    pub owner: Option<u32>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Entity {
+    pub val: Value,
+    pub version: u32,
+    pub restrictions: Restrictions,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ClientSummary {
+    pub id: u32,
+    pub name: String,
+}
+
+/// A lightweight summary of a room for the mgmt API.
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub code: String,
+    pub player_count: usize,
+    pub age_secs: u64,
+}
+
+/// A message a room actor fans out over its broadcast channel.
+#[derive(Clone)]
+pub enum RoomBroadcast {
+    Event {
+        /// `None` means every connected client; `Some(id)` is a private relay.
+        target: Option<u32>,
+        payload: String,
+    },
+    /// The room was force-closed by an operator; every socket should close.
+    Close,
+}
+
+#[derive(Serialize)]
+struct ObjectEvent<'a> {
+    pc: u32,
+    opcode: &'static str,
+    result: ObjectResult<'a>,
+}
+
+#[derive(Serialize)]
+struct ObjectResult<'a> {
+    key: &'a str,
+    val: &'a Value,
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct ClientSendEvent {
+    pc: u32,
+    opcode: &'static str,
+    result: ClientSendResult,
+}
+
+#[derive(Serialize)]
+struct ClientSendResult {
+    from: u32,
+    params: Value,
+}
+
+pub struct JoinResult {
+    pub entities: HashMap<String, Entity>,
+    pub here: Vec<ClientSummary>,
+    pub broadcast_rx: broadcast::Receiver<RoomBroadcast>,
+}
+
+enum RoomCommand {
+    Join {
+        id: u32,
+        name: String,
+        reply: oneshot::Sender<JoinResult>,
+    },
+    Leave {
+        id: u32,
+    },
+    SetObject {
+        from: u32,
+        role: Role,
+        key: String,
+        val: Value,
+    },
+    SendPrivate {
+        from: u32,
+        target: u32,
+        val: Value,
+    },
+    Snapshot {
+        reply: oneshot::Sender<(HashMap<String, Entity>, Vec<ClientSummary>)>,
+    },
+    Close,
+}
+
+/// A cheaply-cloneable handle to a running room actor.
+#[derive(Clone)]
+pub struct RoomHandle {
+    pub code: String,
+    pub token: String,
+    /// Argon2 hash of the room's password, if it's locked.
+    pub password_hash: Option<String>,
+    pub created_at: std::time::Instant,
+    cmd_tx: mpsc::Sender<RoomCommand>,
+    next_client_id: Arc<AtomicU32>,
+}
+
+impl RoomHandle {
+    /// Allocate a new client id for a socket about to join this room.
+    pub fn allocate_client_id(&self) -> u32 {
+        self.next_client_id.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Returns `None` if the room actor has already exited - e.g. an
+    /// operator closed the room while this client was still completing its
+    /// join checks. Callers should treat that as a clean "room gone" close
+    /// rather than a panic.
+    pub async fn join(&self, id: u32, name: String) -> Option<JoinResult> {
+        let (reply, rx) = oneshot::channel();
+        self.cmd_tx
+            .send(RoomCommand::Join { id, name, reply })
+            .await
+            .ok()?;
+        rx.await.ok()
+    }
+
+    pub async fn leave(&self, id: u32) {
+        let _ = self.cmd_tx.send(RoomCommand::Leave { id }).await;
+    }
+
+    /// Handle an incoming `object/set` or `text/set` opcode.
+    pub async fn set_object(&self, from: u32, role: Role, key: String, val: Value) {
+        let _ = self
+            .cmd_tx
+            .send(RoomCommand::SetObject {
+                from,
+                role,
+                key,
+                val,
+            })
+            .await;
+    }
+
+    /// Handle an incoming `client/send` opcode, relaying to `target` only.
+    pub async fn send_private(&self, from: u32, target: u32, val: Value) {
+        let _ = self
+            .cmd_tx
+            .send(RoomCommand::SendPrivate { from, target, val })
+            .await;
+    }
+
+    pub async fn snapshot(&self) -> (HashMap<String, Entity>, Vec<ClientSummary>) {
+        let (reply, rx) = oneshot::channel();
+        let _ = self.cmd_tx.send(RoomCommand::Snapshot { reply }).await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Force-terminate the room: every connected socket is told to close.
+    pub async fn close(&self) {
+        let _ = self.cmd_tx.send(RoomCommand::Close).await;
+    }
+}
+
+struct RoomActor {
+    code: String,
+    entities: HashMap<String, Entity>,
+    clients: HashMap<u32, ClientSummary>,
+    broadcast_tx: broadcast::Sender<RoomBroadcast>,
+    storage: Arc<Storage>,
+}
+
+impl RoomActor {
+    /// Unlike [`RoomRegistry::spawn_gc`], this task isn't wrapped in a
+    /// [`Terminator`]: every `SetObject` is persisted synchronously before
+    /// it's acknowledged, so there's no in-memory state an unsupervised
+    /// shutdown could lose - the task simply exits once every `RoomHandle`
+    /// (and its `cmd_tx`) is dropped.
+    fn run(mut self, mut cmd_rx: mpsc::Receiver<RoomCommand>) {
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                if !self.handle(cmd).await {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Returns `false` when the actor should stop processing further
+    /// commands (only `Close` does this).
+    async fn handle(&mut self, cmd: RoomCommand) -> bool {
+        match cmd {
+            RoomCommand::Join { id, name, reply } => {
+                self.clients.insert(id, ClientSummary { id, name });
+                let _ = reply.send(JoinResult {
+                    entities: self.entities.clone(),
+                    here: self.clients.values().cloned().collect(),
+                    broadcast_rx: self.broadcast_tx.subscribe(),
+                });
+            }
+            RoomCommand::Leave { id } => {
+                self.clients.remove(&id);
+            }
+            RoomCommand::SetObject {
+                from,
+                role,
+                key,
+                val,
+            } => {
+                if !can_write(&self.entities, &key, from, role) {
+                    return true;
+                }
+                let version = self
+                    .entities
+                    .get(&key)
+                    .map(|e| e.version + 1)
+                    .unwrap_or(1);
+                let owner = match role {
+                    Role::Host => None,
+                    Role::Player => Some(from),
+                };
+                let entity = Entity {
+                    val: val.clone(),
+                    version,
+                    restrictions: Restrictions { owner },
+                };
+                if let Err(err) = self.storage.upsert_entity(&self.code, &key, &entity).await {
+                    tracing::warn!("failed to persist entity {}/{}: {}", self.code, key, err);
+                }
+                if let Err(err) = self.storage.touch_room(&self.code, now()).await {
+                    tracing::warn!("failed to touch room {}: {}", self.code, err);
+                }
+                self.entities.insert(key.clone(), entity);
+                let pc = PC.fetch_add(1, Ordering::AcqRel);
+                let payload = serde_json::to_string(&ObjectEvent {
+                    pc,
+                    opcode: "object",
+                    result: ObjectResult {
+                        key: &key,
+                        val: &val,
+                        version,
+                    },
+                })
+                .expect("serializing an object event cannot fail");
+                let _ = self.broadcast_tx.send(RoomBroadcast::Event {
+                    target: None,
+                    payload,
+                });
+            }
+            RoomCommand::SendPrivate { from, target, val } => {
+                let pc = PC.fetch_add(1, Ordering::AcqRel);
+                let payload = serde_json::to_string(&ClientSendEvent {
+                    pc,
+                    opcode: "client/send",
+                    result: ClientSendResult { from, params: val },
+                })
+                .expect("serializing a client/send event cannot fail");
+                let _ = self.broadcast_tx.send(RoomBroadcast::Event {
+                    target: Some(target),
+                    payload,
+                });
+            }
+            RoomCommand::Snapshot { reply } => {
+                let _ = reply.send((
+                    self.entities.clone(),
+                    self.clients.values().cloned().collect(),
+                ));
+            }
+            RoomCommand::Close => {
+                let _ = self.broadcast_tx.send(RoomBroadcast::Close);
+                return false;
+            }
+        }
+        true
+    }
+
+}
+
+/// Hosts may write any key; players may only write keys they already own,
+/// or keys nobody has claimed yet (first write wins). A free function (not
+/// a `RoomActor` method) so the ACL logic is testable without spinning up a
+/// real actor/storage connection.
+fn can_write(entities: &HashMap<String, Entity>, key: &str, from: u32, role: Role) -> bool {
+    match role {
+        Role::Host => true,
+        Role::Player => match entities.get(key) {
+            Some(entity) => entity.restrictions.owner == Some(from),
+            None => true,
+        },
+    }
+}
+
+/// Registry of currently-live rooms, keyed by their 4-letter room code.
+#[derive(Clone)]
+pub struct RoomRegistry {
+    rooms: Arc<RwLock<HashMap<String, RoomHandle>>>,
+    storage: Arc<Storage>,
+    shutdown_tx: broadcast::Sender<()>,
+}
+
+impl RoomRegistry {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        Self {
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            storage,
+            shutdown_tx,
+        }
+    }
+
+    /// Subscribe to the shutdown signal; every connected socket listens on
+    /// this so it can close with a proper close frame instead of being
+    /// dropped mid-flight.
+    pub fn subscribe_shutdown(&self) -> broadcast::Receiver<()> {
+        self.shutdown_tx.subscribe()
+    }
+
+    /// Tell every connected socket to wind down.
+    pub fn begin_shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+
+    /// Allocate a fresh room with a random 4-letter code and a random token.
+    /// `password` locks the room: joining it will require the same password.
+    ///
+    /// Retries on a room-code collision (the `rooms.code` primary key is the
+    /// authoritative uniqueness check) and returns any other storage error
+    /// instead of registering a room nothing actually persisted.
+    pub async fn create_room(&self, password: Option<&str>) -> Result<RoomHandle, sqlx::Error> {
+        let password_hash = password.map(crate::auth::hash_password);
+
+        loop {
+            let code = generate_room_code();
+            let token = generate_token();
+
+            match self
+                .storage
+                .create_room(&code, &token, password_hash.as_deref(), now())
+                .await
+            {
+                Ok(()) => {
+                    let handle = self.spawn_actor(code, token, password_hash, HashMap::new(), 1);
+                    self.rooms
+                        .write()
+                        .await
+                        .insert(handle.code.clone(), handle.clone());
+                    crate::metrics::ACTIVE_ROOMS.inc();
+                    return Ok(handle);
+                }
+                Err(err) if is_unique_violation(&err) => {
+                    tracing::debug!("room code {} collided, regenerating", code);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Re-spawn any rooms that were still active in storage when the
+    /// process last stopped, so in-flight games survive a restart.
+    pub async fn restore(&self) {
+        let codes = match self.storage.active_room_codes().await {
+            Ok(codes) => codes,
+            Err(err) => {
+                tracing::warn!("failed to list persisted rooms: {}", err);
+                return;
+            }
+        };
+
+        for code in codes {
+            let Ok(Some(persisted)) = self.storage.load_room(&code).await else {
+                continue;
+            };
+
+            // The process may have stopped ungracefully with clients still
+            // marked connected; reset them so `find_reconnect` (which only
+            // matches `connected = 0`) can seat them again.
+            if let Err(err) = self.storage.mark_all_disconnected(&code).await {
+                tracing::warn!(
+                    "failed to reset connected clients for room {}: {}",
+                    code,
+                    err
+                );
+            }
+
+            let next_id = persisted.clients.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+            let handle = self.spawn_actor(
+                code.clone(),
+                persisted.token,
+                persisted.password_hash,
+                persisted.entities,
+                next_id,
+            );
+            self.rooms.write().await.insert(code, handle);
+            crate::metrics::ACTIVE_ROOMS.inc();
+        }
+    }
+
+    pub async fn get(&self, code: &str) -> Option<RoomHandle> {
+        self.rooms.read().await.get(code).cloned()
+    }
+
+    /// Remove a room from the in-memory registry, returning its handle if
+    /// it was still present.
+    async fn remove(&self, code: &str) -> Option<RoomHandle> {
+        let removed = self.rooms.write().await.remove(code);
+        if removed.is_some() {
+            crate::metrics::ACTIVE_ROOMS.dec();
+        }
+        removed
+    }
+
+    /// Summaries of every currently-open room, for the mgmt API.
+    pub async fn list_summaries(&self) -> Vec<RoomSummary> {
+        let rooms: Vec<RoomHandle> = self.rooms.read().await.values().cloned().collect();
+        let mut summaries = Vec::with_capacity(rooms.len());
+        for room in rooms {
+            let (_, clients) = room.snapshot().await;
+            summaries.push(RoomSummary {
+                code: room.code.clone(),
+                player_count: clients.len(),
+                age_secs: room.created_at.elapsed().as_secs(),
+            });
+        }
+        summaries
+    }
+
+    /// The full entity store and client roster of one room, for the mgmt
+    /// API's room-dump endpoint.
+    pub async fn dump(&self, code: &str) -> Option<(HashMap<String, Entity>, Vec<ClientSummary>)> {
+        let room = self.get(code).await?;
+        Some(room.snapshot().await)
+    }
+
+    /// Force-terminate a room: tells its sockets to close, permanently
+    /// deletes its persisted state (so a restart's [`Self::restore`]
+    /// doesn't resurrect it), and drops it from the registry. Returns
+    /// `false` if no such room was open.
+    pub async fn close_room(&self, code: &str) -> bool {
+        let Some(room) = self.remove(code).await else {
+            return false;
+        };
+        room.close().await;
+        if let Err(err) = self.storage.delete_room(code).await {
+            tracing::warn!("failed to delete persisted room {}: {}", code, err);
+        }
+        true
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Periodically delete rooms that have had no activity for `ttl`.
+    /// Returns a [`Terminator`] the caller can use to stop the sweep
+    /// cleanly during shutdown.
+    pub fn spawn_gc(&self, ttl: Duration, interval: Duration) -> Terminator {
+        let registry = self.clone();
+        Terminator::spawn(move |mut stop| async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        match registry.storage.gc_expired(ttl, now()).await {
+                            Ok(expired) => {
+                                for code in expired {
+                                    registry.remove(&code).await;
+                                }
+                            }
+                            Err(err) => tracing::warn!("room GC sweep failed: {}", err),
+                        }
+                    }
+                    _ = &mut stop => break,
+                }
+            }
+        })
+    }
+
+    fn spawn_actor(
+        &self,
+        code: String,
+        token: String,
+        password_hash: Option<String>,
+        entities: HashMap<String, Entity>,
+        next_id: u32,
+    ) -> RoomHandle {
+        let (broadcast_tx, _) = broadcast::channel(ROOM_EVENT_CHANNEL);
+        let (cmd_tx, cmd_rx) = mpsc::channel(ROOM_COMMAND_CHANNEL);
+
+        let handle = RoomHandle {
+            code: code.clone(),
+            token,
+            password_hash,
+            created_at: std::time::Instant::now(),
+            cmd_tx,
+            next_client_id: Arc::new(AtomicU32::new(next_id)),
+        };
+
+        RoomActor {
+            code,
+            entities,
+            clients: HashMap::new(),
+            broadcast_tx,
+            storage: self.storage.clone(),
+        }
+        .run(cmd_rx);
+
+        handle
+    }
+}
+
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn generate_room_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ROOM_CODE_LEN)
+        .map(|_| ROOM_CODE_ALPHABET[rng.gen_range(0..ROOM_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..24).map(|_| rng.gen_range(0..10).to_string()).collect()
+}
+
+/// Whether `err` is a primary-key/unique-constraint violation, i.e. a
+/// genuine room-code collision rather than some other storage failure.
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Database(db_err) if db_err.is_unique_violation())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity_owned_by(owner: u32) -> Entity {
+        Entity {
+            val: Value::Null,
+            version: 1,
+            restrictions: Restrictions { owner: Some(owner) },
+        }
+    }
+
+    #[test]
+    fn host_can_write_any_key() {
+        let mut entities = HashMap::new();
+        entities.insert("k".to_string(), entity_owned_by(1));
+        assert!(can_write(&entities, "k", 2, Role::Host));
+    }
+
+    #[test]
+    fn player_can_write_unclaimed_key() {
+        let entities = HashMap::new();
+        assert!(can_write(&entities, "k", 1, Role::Player));
+    }
+
+    #[test]
+    fn player_can_write_key_they_own() {
+        let mut entities = HashMap::new();
+        entities.insert("k".to_string(), entity_owned_by(1));
+        assert!(can_write(&entities, "k", 1, Role::Player));
+    }
+
+    #[test]
+    fn player_cannot_write_key_owned_by_another_player() {
+        let mut entities = HashMap::new();
+        entities.insert("k".to_string(), entity_owned_by(1));
+        assert!(!can_write(&entities, "k", 2, Role::Player));
+    }
+}
@@ -0,0 +1,64 @@
+//! A small supervision primitive for tasks that need to be told to stop
+//! and waited on, rather than detached and forgotten.
+
+use std::future::Future;
+
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Spawns a task that owns a [`oneshot::Receiver`] it can `select!` against
+/// to know when to wind down. `terminate().await` signals the task and
+/// joins it, so callers can be sure cleanup has actually finished before
+/// moving on.
+pub struct Terminator {
+    signal: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+impl Terminator {
+    pub fn spawn<F, Fut>(task: F) -> Self
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (signal, rx) = oneshot::channel();
+        let handle = tokio::spawn(task(rx));
+        Self {
+            signal: Some(signal),
+            handle,
+        }
+    }
+
+    /// Signal the task to stop and wait for it to actually finish.
+    pub async fn terminate(mut self) {
+        if let Some(signal) = self.signal.take() {
+            let _ = signal.send(());
+        }
+        let _ = self.handle.await;
+    }
+}
+
+/// Resolves on Ctrl-C or, on Unix, `SIGTERM` - whichever arrives first.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,328 @@
+//! SQLite-backed persistence for rooms, so a dropped connection or a
+//! server restart doesn't throw away a game in progress.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use sqlx::Row;
+
+use crate::room::{Entity, Restrictions, Role};
+
+pub struct PersistedClient {
+    pub id: u32,
+    pub user_id: String,
+    pub secret: String,
+    pub name: String,
+    pub role: Role,
+}
+
+pub struct PersistedRoom {
+    pub token: String,
+    pub password_hash: Option<String>,
+    pub entities: HashMap<String, Entity>,
+    pub clients: Vec<PersistedClient>,
+}
+
+pub struct Storage {
+    pool: SqlitePool,
+}
+
+impl Storage {
+    pub async fn connect(path: &Path) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        let storage = Self { pool };
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    async fn migrate(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS rooms (
+                code TEXT PRIMARY KEY,
+                token TEXT NOT NULL,
+                password_hash TEXT,
+                created_at INTEGER NOT NULL,
+                last_active INTEGER NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS clients (
+                room_code TEXT NOT NULL REFERENCES rooms(code) ON DELETE CASCADE,
+                id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                name TEXT NOT NULL,
+                role TEXT NOT NULL,
+                connected INTEGER NOT NULL,
+                PRIMARY KEY (room_code, id)
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS entities (
+                room_code TEXT NOT NULL REFERENCES rooms(code) ON DELETE CASCADE,
+                key TEXT NOT NULL,
+                val TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                owner INTEGER,
+                PRIMARY KEY (room_code, key)
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_room(
+        &self,
+        code: &str,
+        token: &str,
+        password_hash: Option<&str>,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO rooms (code, token, password_hash, created_at, last_active)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(code)
+        .bind(token)
+        .bind(password_hash)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Permanently delete a room and its clients/entities (via `ON DELETE
+    /// CASCADE`), used when an operator force-closes a room so it doesn't
+    /// get resurrected by `RoomRegistry::restore` on the next restart.
+    pub async fn delete_room(&self, code: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM rooms WHERE code = ?")
+            .bind(code)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn touch_room(&self, code: &str, now: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE rooms SET last_active = ? WHERE code = ?")
+            .bind(now)
+            .bind(code)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn upsert_client(
+        &self,
+        room_code: &str,
+        id: u32,
+        user_id: &str,
+        secret: &str,
+        name: &str,
+        role: Role,
+        connected: bool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO clients (room_code, id, user_id, secret, name, role, connected)
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (room_code, id) DO UPDATE SET
+                user_id = excluded.user_id,
+                secret = excluded.secret,
+                name = excluded.name,
+                role = excluded.role,
+                connected = excluded.connected",
+        )
+        .bind(room_code)
+        .bind(id as i64)
+        .bind(user_id)
+        .bind(secret)
+        .bind(name)
+        .bind(role_to_str(role))
+        .bind(connected)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn mark_disconnected(&self, room_code: &str, id: u32) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE clients SET connected = 0 WHERE room_code = ? AND id = ?")
+            .bind(room_code)
+            .bind(id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Mark every client in `room_code` disconnected, so a room restored
+    /// after an ungraceful shutdown doesn't leave clients stuck `connected
+    /// = 1` forever - `find_reconnect` only matches `connected = 0` rows.
+    pub async fn mark_all_disconnected(&self, room_code: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE clients SET connected = 0 WHERE room_code = ?")
+            .bind(room_code)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Find a disconnected client in `room_code` matching `user_id` and
+    /// `secret`, so a refreshed page can rejoin its old seat.
+    pub async fn find_reconnect(
+        &self,
+        room_code: &str,
+        user_id: &str,
+        secret: &str,
+    ) -> Result<Option<PersistedClient>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT id, user_id, secret, name, role FROM clients
+             WHERE room_code = ? AND user_id = ? AND secret = ? AND connected = 0",
+        )
+        .bind(room_code)
+        .bind(user_id)
+        .bind(secret)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| PersistedClient {
+            id: row.get::<i64, _>("id") as u32,
+            user_id: row.get("user_id"),
+            secret: row.get("secret"),
+            name: row.get("name"),
+            role: role_from_str(&row.get::<String, _>("role")),
+        }))
+    }
+
+    pub async fn upsert_entity(
+        &self,
+        room_code: &str,
+        key: &str,
+        entity: &Entity,
+    ) -> Result<(), sqlx::Error> {
+        let val = serde_json::to_string(&entity.val).unwrap_or_default();
+        sqlx::query(
+            "INSERT INTO entities (room_code, key, val, version, owner)
+             VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT (room_code, key) DO UPDATE SET
+                val = excluded.val,
+                version = excluded.version,
+                owner = excluded.owner",
+        )
+        .bind(room_code)
+        .bind(key)
+        .bind(val)
+        .bind(entity.version as i64)
+        .bind(entity.restrictions.owner.map(|owner| owner as i64))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Load a room's persisted state, used to restore rooms that were still
+    /// active across a server restart.
+    pub async fn load_room(&self, code: &str) -> Result<Option<PersistedRoom>, sqlx::Error> {
+        let Some(room_row) = sqlx::query("SELECT token, password_hash FROM rooms WHERE code = ?")
+            .bind(code)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let token: String = room_row.get("token");
+        let password_hash: Option<String> = room_row.get("password_hash");
+
+        let entity_rows = sqlx::query("SELECT key, val, version, owner FROM entities WHERE room_code = ?")
+            .bind(code)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut entities = HashMap::new();
+        for row in entity_rows {
+            let key: String = row.get("key");
+            let val: String = row.get("val");
+            entities.insert(
+                key,
+                Entity {
+                    val: serde_json::from_str(&val).unwrap_or(serde_json::Value::Null),
+                    version: row.get::<i64, _>("version") as u32,
+                    restrictions: Restrictions {
+                        owner: row.get::<Option<i64>, _>("owner").map(|id| id as u32),
+                    },
+                },
+            );
+        }
+
+        let client_rows = sqlx::query(
+            "SELECT id, user_id, secret, name, role FROM clients WHERE room_code = ?",
+        )
+        .bind(code)
+        .fetch_all(&self.pool)
+        .await?;
+        let clients = client_rows
+            .into_iter()
+            .map(|row| PersistedClient {
+                id: row.get::<i64, _>("id") as u32,
+                user_id: row.get("user_id"),
+                secret: row.get("secret"),
+                name: row.get("name"),
+                role: role_from_str(&row.get::<String, _>("role")),
+            })
+            .collect();
+
+        Ok(Some(PersistedRoom {
+            token,
+            password_hash,
+            entities,
+            clients,
+        }))
+    }
+
+    pub async fn active_room_codes(&self) -> Result<Vec<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT code FROM rooms")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("code")).collect())
+    }
+
+    /// Delete rooms (and their clients/entities, via `ON DELETE CASCADE`)
+    /// that have had no activity within `ttl`.
+    pub async fn gc_expired(&self, ttl: Duration, now: i64) -> Result<Vec<String>, sqlx::Error> {
+        let cutoff = now - ttl.as_secs() as i64;
+        let rows = sqlx::query("SELECT code FROM rooms WHERE last_active < ?")
+            .bind(cutoff)
+            .fetch_all(&self.pool)
+            .await?;
+        let expired: Vec<String> = rows.into_iter().map(|row| row.get("code")).collect();
+
+        sqlx::query("DELETE FROM rooms WHERE last_active < ?")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(expired)
+    }
+}
+
+fn role_to_str(role: Role) -> &'static str {
+    match role {
+        Role::Host => "host",
+        Role::Player => "player",
+    }
+}
+
+fn role_from_str(role: &str) -> Role {
+    match role {
+        "host" => Role::Host,
+        _ => Role::Player,
+    }
+}